@@ -1,40 +1,434 @@
-use std::collections::LinkedList;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::data_source::DataSource;
 
 type VirtualAddress = usize;
 
+/// Identifies the borrow that is acquiring or releasing a lock, e.g. a thread, a fiber, or a
+/// nested call frame that needs exclusive or shared access to part of an `AddressSpace`.
+/// Modeled loosely on the tags Miri's stacked-borrows checker attaches to references.
+pub type Lifetime = usize;
+
+/// A nested scope that a suspended write lock is waiting on; the lock can only reactivate once
+/// every extent recorded against it has ended.
+pub type Extent = usize;
+
+/// A half-open `[start, start + len)` range of virtual addresses, used as the key for borrow
+/// tracking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AddrRange {
+    pub start: VirtualAddress,
+    pub len: usize,
+}
+
+impl AddrRange {
+    #[must_use]
+    pub const fn overlaps(self, other: Self) -> bool {
+        self.start < other.start + other.len && other.start < self.start + self.len
+    }
+}
+
+/// What kind of access a lock (or an access check) concerns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    Read,
+    Write,
+}
+
+/// The lock currently held over a range, if any.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Lock {
+    #[default]
+    NoLock,
+    WriteLock(Lifetime),
+    ReadLock(Vec<Lifetime>),
+}
+
+/// The borrow state of a mapped byte range: the currently active lock, plus any write locks
+/// that have been temporarily suspended (e.g. so a nested scope can read) along with the
+/// extents that must all end before each can reactivate.
+#[derive(Clone, Debug, Default)]
+pub struct LockInfo {
+    active: Lock,
+    suspended: HashMap<Lifetime, Vec<Extent>>,
+}
+
+/// The granularity at which a copy-on-write fault copies bytes out of the parent `DataSource`.
+const COW_PAGE_SIZE: usize = 4096;
+
+/// The private backing store created the first time a `cow` mapping is written to.
+///
+/// Holds a plain copy of the touched bytes from the parent `DataSource`; writes after the
+/// fault land here instead of the shared parent.
+struct CowCopy {
+    bytes: Vec<u8>,
+}
+
+impl DataSource for CowCopy {
+    fn get_bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.bytes[offset..offset + len].to_vec()
+    }
+}
+
 struct MapEntry {
     source: Arc<dyn DataSource>, //TODO: make methods not require Arcs
     offset: usize,
     span: usize,
     addr: usize,
+    flags: FlagBuilder,
 }
 
+// Mappings used to live in a `LinkedList<MapEntry>` (see
+// https://github.com/dylanmc/cs393_vm_api/issues/10), which made every insertion, lookup, and
+// overlap check an O(n) walk, and the hand-rolled overlap tests were inclusive at the range
+// boundaries, so two mappings that merely touched at a shared byte were rejected as
+// overlapping. `interval_tree` replaces it with a balanced (AVL) tree keyed by the half-open
+// `[addr, addr + span)` range of each mapping, built only out of `Box`/`Option` so it stays
+// `#no_std`-friendly, with one overlap predicate shared by insertion, point lookup, and removal.
+mod interval_tree {
+    use super::MapEntry;
+    use std::cmp::Ordering;
+
+    /// Two half-open `[start, end)` ranges overlap iff each starts before the other ends.
+    /// Ranges that merely touch at a shared endpoint (`a.end == b.start`) do not overlap.
+    fn overlaps(a_start: usize, a_end: usize, b_start: usize, b_end: usize) -> bool {
+        a_start < b_end && b_start < a_end
+    }
+
+    struct Node {
+        start: usize,
+        end: usize,
+        entry: MapEntry,
+        height: i32,
+        /// The smallest `start` anywhere in this subtree (i.e. the leftmost node's `start`).
+        subtree_min_start: usize,
+        /// The largest `end` anywhere in this subtree. Since mappings are disjoint and this
+        /// tree is keyed in `start` order, `end` is non-decreasing in the same order, so this
+        /// is always the rightmost node's `end`.
+        subtree_max_end: usize,
+        /// The largest gap between two (or at the edge of one) mappings strictly inside this
+        /// subtree's own span, i.e. not counting the free space before `subtree_min_start` or
+        /// after `subtree_max_end` (those depend on mappings outside this subtree).
+        max_gap: usize,
+        left: Option<Box<Node>>,
+        right: Option<Box<Node>>,
+    }
+
+    fn height(node: &Option<Box<Node>>) -> i32 {
+        node.as_ref().map_or(0, |n| n.height)
+    }
+
+    /// Recompute `height` and the gap-search augmentation from `node`'s (already up to date)
+    /// children. Must be called after any change to `node.left` or `node.right`.
+    fn update(node: &mut Node) {
+        node.height = 1 + height(&node.left).max(height(&node.right));
+
+        node.subtree_min_start = node.left.as_ref().map_or(node.start, |l| l.subtree_min_start);
+        node.subtree_max_end = node.right.as_ref().map_or(node.end, |r| r.subtree_max_end);
+
+        let mut max_gap = 0;
+        if let Some(l) = &node.left {
+            max_gap = max_gap.max(l.max_gap).max(node.start - l.subtree_max_end);
+        }
+        if let Some(r) = &node.right {
+            max_gap = max_gap.max(r.max_gap).max(r.subtree_min_start - node.end);
+        }
+        node.max_gap = max_gap;
+    }
+
+    fn balance_factor(node: &Node) -> i32 {
+        height(&node.left) - height(&node.right)
+    }
+
+    fn rotate_right(mut node: Box<Node>) -> Box<Node> {
+        let mut left = node.left.take().expect("rotate_right requires a left child");
+        node.left = left.right.take();
+        update(&mut node);
+        left.right = Some(node);
+        update(&mut left);
+        left
+    }
+
+    fn rotate_left(mut node: Box<Node>) -> Box<Node> {
+        let mut right = node.right.take().expect("rotate_left requires a right child");
+        node.right = right.left.take();
+        update(&mut node);
+        right.left = Some(node);
+        update(&mut right);
+        right
+    }
+
+    fn rebalance(mut node: Box<Node>) -> Box<Node> {
+        update(&mut node);
+        match balance_factor(&node) {
+            bf if bf > 1 => {
+                if balance_factor(node.left.as_ref().expect("bf > 1 implies a left child")) < 0 {
+                    node.left = Some(rotate_left(node.left.take().expect("checked above")));
+                }
+                rotate_right(node)
+            }
+            bf if bf < -1 => {
+                if balance_factor(node.right.as_ref().expect("bf < -1 implies a right child")) > 0
+                {
+                    node.right = Some(rotate_right(node.right.take().expect("checked above")));
+                }
+                rotate_left(node)
+            }
+            _ => node,
+        }
+    }
+
+    /// Insert `[start, end)` keyed by `start`, rejecting (and handing back, along with the
+    /// subtree rooted here, unchanged) `entry` if it overlaps an existing mapping.
+    ///
+    /// The subtree has to come back on the error path too: a plain `Result<Box<Node>, MapEntry>`
+    /// with `?` would propagate a deep rejection straight up through every stack frame, dropping
+    /// each frame's (still-needed) `n` along the way and leaving the whole tree destroyed rather
+    /// than just refusing the one insert.
+    fn insert(
+        node: Option<Box<Node>>,
+        start: usize,
+        end: usize,
+        entry: MapEntry,
+    ) -> Result<Box<Node>, (Option<Box<Node>>, MapEntry)> {
+        match node {
+            None => Ok(Box::new(Node {
+                start,
+                end,
+                entry,
+                height: 1,
+                subtree_min_start: start,
+                subtree_max_end: end,
+                max_gap: 0,
+                left: None,
+                right: None,
+            })),
+            Some(mut n) => {
+                if overlaps(start, end, n.start, n.end) {
+                    return Err((Some(n), entry));
+                }
+                if start < n.start {
+                    match insert(n.left.take(), start, end, entry) {
+                        Ok(new_left) => {
+                            n.left = Some(new_left);
+                            Ok(rebalance(n))
+                        }
+                        Err((old_left, entry)) => {
+                            n.left = old_left;
+                            Err((Some(n), entry))
+                        }
+                    }
+                } else {
+                    match insert(n.right.take(), start, end, entry) {
+                        Ok(new_right) => {
+                            n.right = Some(new_right);
+                            Ok(rebalance(n))
+                        }
+                        Err((old_right, entry)) => {
+                            n.right = old_right;
+                            Err((Some(n), entry))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn min_key(node: &Node) -> usize {
+        node.left.as_ref().map_or(node.start, |l| min_key(l))
+    }
+
+    /// Remove the node keyed by exactly `start`, returning its `(end, entry)` if present.
+    fn remove(
+        node: Option<Box<Node>>,
+        start: usize,
+    ) -> (Option<Box<Node>>, Option<(usize, MapEntry)>) {
+        let Some(mut n) = node else {
+            return (None, None);
+        };
+        match start.cmp(&n.start) {
+            Ordering::Less => {
+                let (left, removed) = remove(n.left.take(), start);
+                n.left = left;
+                (Some(rebalance(n)), removed)
+            }
+            Ordering::Greater => {
+                let (right, removed) = remove(n.right.take(), start);
+                n.right = right;
+                (Some(rebalance(n)), removed)
+            }
+            Ordering::Equal => match (n.left.take(), n.right.take()) {
+                (None, None) => (None, Some((n.end, n.entry))),
+                (Some(l), None) => (Some(l), Some((n.end, n.entry))),
+                (None, Some(r)) => (Some(r), Some((n.end, n.entry))),
+                (Some(l), Some(r)) => {
+                    let successor_start = min_key(&r);
+                    let (new_right, successor) = remove(Some(r), successor_start);
+                    let (successor_end, successor_entry) =
+                        successor.expect("min_key names a node that is present in this subtree");
+                    let removed = (n.end, std::mem::replace(&mut n.entry, successor_entry));
+                    n.start = successor_start;
+                    n.end = successor_end;
+                    n.left = Some(l);
+                    n.right = new_right;
+                    (Some(rebalance(n)), Some(removed))
+                }
+            },
+        }
+    }
+
+    /// Find the node whose half-open range contains `point`.
+    ///
+    /// Relies on mappings being disjoint (enforced by `insert`): for any node `n`, every
+    /// interval in its left subtree ends at or before `n.start` and every interval in its
+    /// right subtree starts at or after `n.end`, so a plain key comparison is enough to pick
+    /// the only subtree that could possibly contain `point`.
+    fn find(node: &Option<Box<Node>>, point: usize) -> Option<&Node> {
+        let n = node.as_ref()?;
+        if overlaps(point, point.saturating_add(1), n.start, n.end) {
+            Some(n)
+        } else if point < n.start {
+            find(&n.left, point)
+        } else {
+            find(&n.right, point)
+        }
+    }
+
+    /// Find the leftmost gap of at least `span` bytes strictly inside `node`'s subtree (i.e.
+    /// between two of its mappings, or at the edge of one of its children), guided by the
+    /// `max_gap` augmentation so only one root-to-candidate path is walked.
+    fn find_gap(node: &Node, span: usize) -> Option<usize> {
+        if let Some(l) = &node.left {
+            if l.max_gap >= span {
+                if let Some(start) = find_gap(l, span) {
+                    return Some(start);
+                }
+            }
+            if node.start - l.subtree_max_end >= span {
+                return Some(l.subtree_max_end);
+            }
+        }
+        if let Some(r) = &node.right {
+            if r.subtree_min_start - node.end >= span {
+                return Some(node.end);
+            }
+            if r.max_gap >= span {
+                return find_gap(r, span);
+            }
+        }
+        None
+    }
+
+    /// Collect the start key of every node whose range overlaps `[q_start, q_end)`.
+    fn collect_overlapping(node: &Option<Box<Node>>, q_start: usize, q_end: usize, out: &mut Vec<usize>) {
+        let Some(n) = node else { return };
+        collect_overlapping(&n.left, q_start, q_end, out);
+        if overlaps(q_start, q_end, n.start, n.end) {
+            out.push(n.start);
+        }
+        collect_overlapping(&n.right, q_start, q_end, out);
+    }
+
+    /// A balanced tree of non-overlapping `[addr, addr + span)` mappings.
+    #[derive(Default)]
+    pub struct IntervalTree {
+        root: Option<Box<Node>>,
+    }
+
+    impl IntervalTree {
+        pub const fn new() -> Self {
+            Self { root: None }
+        }
+
+        /// Insert a mapping spanning `[start, start + span)`.
+        ///
+        /// # Errors
+        /// Hands `entry` back if it overlaps a mapping already in the tree.
+        pub fn insert(&mut self, start: usize, span: usize, entry: MapEntry) -> Result<(), MapEntry> {
+            match insert(self.root.take(), start, start + span, entry) {
+                Ok(root) => {
+                    self.root = Some(root);
+                    Ok(())
+                }
+                Err((root, entry)) => {
+                    self.root = root;
+                    Err(entry)
+                }
+            }
+        }
+
+        /// Remove the mapping that starts at exactly `start`.
+        pub fn remove_start(&mut self, start: usize) -> Option<MapEntry> {
+            let (root, removed) = remove(self.root.take(), start);
+            self.root = root;
+            removed.map(|(_, entry)| entry)
+        }
+
+        /// Remove the mapping whose range contains `point`, returning its `(start, span, entry)`.
+        pub fn remove_containing(&mut self, point: usize) -> Option<(usize, usize, MapEntry)> {
+            let start = find(&self.root, point)?.start;
+            let (root, removed) = remove(self.root.take(), start);
+            self.root = root;
+            removed.map(|(end, entry)| (start, end - start, entry))
+        }
+
+        /// Look up the mapping whose range contains `point`.
+        pub fn get_containing(&self, point: usize) -> Option<(usize, usize, &MapEntry)> {
+            let n = find(&self.root, point)?;
+            Some((n.start, n.end, &n.entry))
+        }
+
+        /// First-fit: the smallest address `>= 0` at which a mapping of `span` bytes fits
+        /// without overlapping an existing one.
+        ///
+        /// Runs in O(log n): each node augments its subtree with the largest internal gap, the
+        /// lowest `start`, and the highest `end`, so the search descends a single root-to-leaf
+        /// path instead of walking every mapping.
+        #[must_use]
+        pub fn first_fit(&self, span: usize) -> usize {
+            let Some(root) = &self.root else {
+                return 0;
+            };
+            if root.subtree_min_start >= span {
+                return 0;
+            }
+            find_gap(root, span).unwrap_or(root.subtree_max_end)
+        }
+
+        /// Remove every mapping whose range overlaps `[start, end)`, returning each as
+        /// `(start, end, entry)` in ascending order of `start`.
+        pub fn take_overlapping(&mut self, start: usize, end: usize) -> Vec<(usize, usize, MapEntry)> {
+            let mut starts = Vec::new();
+            collect_overlapping(&self.root, start, end, &mut starts);
+            starts
+                .into_iter()
+                .filter_map(|s| {
+                    let (root, removed) = remove(self.root.take(), s);
+                    self.root = root;
+                    removed.map(|(e, entry)| (s, e, entry))
+                })
+                .collect()
+        }
+    }
+}
+
+use interval_tree::IntervalTree;
+
 /// An address space.
 pub struct AddressSpace {
     name: String,
-    mappings: LinkedList<MapEntry>, // see below for comments
+    mappings: IntervalTree,
+    locks: Vec<(AddrRange, LockInfo)>,
 }
 
-// comments about storing mappings
-// Most OS code uses doubly-linked lists to store sparse data structures like
-// an address space's mappings.
-// Using Rust's built-in LinkedLists is fine. See https://doc.rust-lang.org/std/collections/struct.LinkedList.html
-// But if you really want to get the zen of Rust, this is a really good read, written by the original author
-// of that very data structure: https://rust-unofficial.github.io/too-many-lists/
-
-// So, feel free to come up with a different structure, either a classic Rust collection,
-// from a crate (but remember it needs to be #no_std compatible), or even write your own.
-// See this ticket from Riley: https://github.com/dylanmc/cs393_vm_api/issues/10
-
 impl AddressSpace {
     #[must_use]
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
-            mappings: LinkedList::new(),
+            mappings: IntervalTree::new(),
+            locks: Vec::new(),
         }
     }
 
@@ -47,43 +441,32 @@ impl AddressSpace {
         source: Arc<D>,
         offset: usize,
         span: usize,
+        flags: FlagBuilder,
     ) -> Result<VirtualAddress, &str> {
-		let spot = self.mappings.iter().fold(0, |spot, x| if x.addr > spot+span {spot} else {x.addr+x.span});
-		self.add_mapping_at(source,offset,span,spot).map(|()| spot)
+		let spot = self.mappings.first_fit(span);
+		self.add_mapping_at(source,offset,span,spot,flags).map(|()| spot)
     }
 
     /// Add a mapping from `DataSource` into this `AddressSpace` starting at a specific address.
     ///
     /// # Errors
-    /// If there is insufficient room subsequent to `start`.
+    /// If there is insufficient room subsequent to `start`, or if `flags` contains a
+    /// conflicting combination (see [`FlagBuilder::validate`]).
     pub fn add_mapping_at<D: 'static+DataSource>(
         &mut self,
         source: Arc<D>,
         offset: usize,
         span: usize,
         start: VirtualAddress,
+        flags: FlagBuilder,
     ) -> Result<(), &str> {
-        let next_map = self.mappings.iter().enumerate().find(|&x| x.1.addr + x.1.span >= start);
-        if next_map.is_none(){
-			if usize::MAX - span <= start {
-				Err("no space for that map")
-			}
-			else{
-				self.mappings.push_back(MapEntry {source: source.clone(),offset,span,addr:start,});//TODO: construct Arc properly
-				Ok(())
-			}
-		}
-		else{
-			if next_map.unwrap().1.addr <= start+span {
-				Err("no space for that map")
-			}
-			else{
-				let mut back_half = self.mappings.split_off(next_map.unwrap().0);
-				back_half.push_front(MapEntry {source: source.clone(),offset,span,addr:start,});
-				self.mappings.append(&mut back_half);//TODO: fix warnings - possibly don't use linked lists?
-				Ok(())
-			}
+        flags.validate().map_err(FlagError::message)?;
+        if usize::MAX - span <= start {
+			return Err("no space for that map");
 		}
+		self.mappings
+			.insert(start, span, MapEntry {source: source.clone(),offset,span,addr:start,flags,})//TODO: construct Arc properly
+			.map_err(|_| "no space for that map")
     }
 
     /// Remove the mapping to `DataSource` that starts at the given address.
@@ -95,39 +478,360 @@ impl AddressSpace {
         source: Arc<D>,
         start: VirtualAddress,
     ) -> Result<(), &str> {//TODO: check source
-        let to_delete = self.mappings.iter().enumerate().find(|&(_,x)| x.addr == start).ok_or("that map doesn't exist");
-        if to_delete.is_err() {
-			return to_delete.map(|_| ());
-		}
-		else{
-        	let mut back_half = self.mappings.split_off(to_delete.unwrap().0);
-			back_half.pop_front();
-			self.mappings.append(&mut back_half);
-       		Ok(())
-       	}
+        self.mappings.remove_start(start).ok_or("that map doesn't exist").map(|_| ())
+    }
+
+    /// Unmap `[start, start + len)`, trimming or splitting whatever mappings it overlaps
+    /// (`munmap` semantics): a mapping entirely inside the range is dropped, one overlapping
+    /// just its low edge is shortened, one overlapping just its high edge has its `addr`,
+    /// `offset`, and `span` advanced past the removed portion, and one that strictly contains
+    /// the range is split into two mappings with a hole left in between.
+    ///
+    /// Returns the number of bytes actually unmapped, which is less than `len` if the range
+    /// wasn't fully covered by existing mappings.
+    pub fn remove_mapping_range(&mut self, start: VirtualAddress, len: usize) -> usize {
+        let end = start + len;
+        let mut unmapped = 0;
+        for (m_start, m_end, entry) in self.mappings.take_overlapping(start, end) {
+            let overlap_start = m_start.max(start);
+            let overlap_end = m_end.min(end);
+            unmapped += overlap_end - overlap_start;
+
+            if overlap_start > m_start {
+                self.mappings
+                    .insert(
+                        m_start,
+                        overlap_start - m_start,
+                        MapEntry {
+                            source: entry.source.clone(),
+                            offset: entry.offset,
+                            span: overlap_start - m_start,
+                            addr: m_start,
+                            flags: entry.flags,
+                        },
+                    )
+                    .unwrap_or_else(|_| unreachable!("the low remainder is inside the space just vacated"));
+            }
+            if overlap_end < m_end {
+                self.mappings
+                    .insert(
+                        overlap_end,
+                        m_end - overlap_end,
+                        MapEntry {
+                            source: entry.source.clone(),
+                            offset: entry.offset + (overlap_end - m_start),
+                            span: m_end - overlap_end,
+                            addr: overlap_end,
+                            flags: entry.flags,
+                        },
+                    )
+                    .unwrap_or_else(|_| unreachable!("the high remainder is inside the space just vacated"));
+            }
+        }
+        unmapped
     }
 
     /// Look up the DataSource and offset within that DataSource for a
     /// VirtualAddress / AccessType in this AddressSpace
-    /// 
+    ///
+    /// A write against a `cow` mapping is routed through `resolve_write`, which splits off a
+    /// private copy of the touched page instead of handing back the shared parent `DataSource`.
+    ///
     /// # Errors
     /// If this VirtualAddress does not have a valid mapping in &self,
-    /// or if this AccessType is not permitted by the mapping
+    /// if this AccessType is not permitted by the mapping, or if `lifetime` is not allowed to
+    /// perform this access under the current borrow-lock state (see `access_permitted`).
     pub fn get_source_for_addr(
-        &self,
+        &mut self,
         addr: VirtualAddress,
-        access_type: FlagBuilder
+        access_type: FlagBuilder,
+        lifetime: Lifetime,
     ) -> Result<(Arc<dyn DataSource>, usize), &str> {
-		if access_type.read {
-			return Err("wrong permissions");
-		}
-        let map = self.mappings.iter().find(|&x| x.addr <= addr && addr <= x.addr + x.span);
-        if map.is_none() {
-			Err("that address isn't mapped")
-		}
-		else{
-			Ok((map.unwrap().source.clone(),map.unwrap().offset))
-		}
+        let (start, end, flags, source, offset) = {
+            let (start, end, entry) =
+                self.mappings.get_containing(addr).ok_or("that address isn't mapped")?;
+            (start, end, entry.flags, entry.source.clone(), entry.offset)
+        };
+        if !access_type.but_not(flags).is_empty() {
+            return Err("wrong permissions");
+        }
+        let kind = if access_type.write { LockKind::Write } else { LockKind::Read };
+        let range = AddrRange { start, len: end - start };
+        if !self.access_permitted(range, kind, lifetime) {
+            return Err("that range is locked against this access");
+        }
+        if access_type.write && flags.cow {
+            return self.resolve_write(addr, lifetime);
+        }
+        Ok((source, offset))
+    }
+
+    /// Acquire a read or write lock over `range` on behalf of `lifetime`.
+    ///
+    /// Checks every existing lock entry that overlaps `range` (not just one keyed by an
+    /// identical range, see `access_permitted`), so a lock can't be granted over a range that
+    /// would leave part of it inconsistently held by two different lifetimes.
+    ///
+    /// # Errors
+    /// If `range` overlaps a write lock held by a different lifetime, or if `kind` is
+    /// `Write` and `range` overlaps a read lock (even one held only by `lifetime` itself).
+    pub fn acquire(&mut self, range: AddrRange, kind: LockKind, lifetime: Lifetime) -> Result<(), &str> {
+        let conflict = self.locks.iter().any(|(r, info)| {
+            r.overlaps(range)
+                && match (&info.active, kind) {
+                    (Lock::NoLock, _) | (Lock::ReadLock(_), LockKind::Read) => false,
+                    (Lock::ReadLock(_), LockKind::Write) => true,
+                    (Lock::WriteLock(owner), _) => *owner != lifetime,
+                }
+        });
+        if conflict {
+            return Err("range overlaps a conflicting lock");
+        }
+
+        let info = self.lock_info_mut(range);
+        match (&info.active, kind) {
+            (Lock::NoLock, LockKind::Read) => {
+                info.active = Lock::ReadLock(vec![lifetime]);
+            }
+            (Lock::NoLock, LockKind::Write) | (Lock::WriteLock(_), _) => {
+                info.active = Lock::WriteLock(lifetime);
+            }
+            (Lock::ReadLock(holders), LockKind::Read) => {
+                let mut holders = holders.clone();
+                if !holders.contains(&lifetime) {
+                    holders.push(lifetime);
+                }
+                info.active = Lock::ReadLock(holders);
+            }
+            // Not actually ruled out by the conflict check above: a zero-length `range` does
+            // not overlap itself (so an identical zero-length range already holding a read
+            // lock slips past the scan), so this arm is still reachable and must fail
+            // gracefully rather than assume the scan above already excluded it.
+            (Lock::ReadLock(_), LockKind::Write) => {
+                return Err("range is already read-locked");
+            }
+        }
+        Ok(())
+    }
+
+    /// Release the lock `lifetime` holds over `range`.
+    ///
+    /// # Errors
+    /// If `lifetime` does not currently hold a lock over `range`.
+    pub fn release(&mut self, range: AddrRange, lifetime: Lifetime) -> Result<(), &str> {
+        let info = self.lock_info_mut(range);
+        match &mut info.active {
+            Lock::NoLock => Err("range is not locked"),
+            Lock::WriteLock(owner) if *owner == lifetime => {
+                info.active = Lock::NoLock;
+                Ok(())
+            }
+            Lock::WriteLock(_) => Err("lifetime does not hold this write lock"),
+            Lock::ReadLock(holders) => {
+                if let Some(pos) = holders.iter().position(|l| *l == lifetime) {
+                    holders.remove(pos);
+                    if holders.is_empty() {
+                        info.active = Lock::NoLock;
+                    }
+                    Ok(())
+                } else {
+                    Err("lifetime does not hold this read lock")
+                }
+            }
+        }
+    }
+
+    /// Temporarily relinquish the write lock `lifetime` holds over `range`, recording that it
+    /// must reactivate once `extent` (and any other outstanding extent for this lifetime) ends.
+    ///
+    /// # Errors
+    /// If `lifetime` does not currently hold the write lock over `range`.
+    pub fn suspend(&mut self, range: AddrRange, lifetime: Lifetime, extent: Extent) -> Result<(), &str> {
+        let info = self.lock_info_mut(range);
+        match &info.active {
+            Lock::WriteLock(owner) if *owner == lifetime => {
+                info.suspended.entry(lifetime).or_default().push(extent);
+                info.active = Lock::NoLock;
+                Ok(())
+            }
+            _ => Err("lifetime does not hold the write lock over this range"),
+        }
+    }
+
+    /// End `extent` for `lifetime`'s suspended write lock over `range`, reactivating the lock
+    /// once every extent it was waiting on has ended.
+    ///
+    /// # Errors
+    /// If `lifetime` has no such outstanding suspension over `range`, or if someone else
+    /// acquired a lock over `range` while it was suspended.
+    pub fn reactivate(&mut self, range: AddrRange, lifetime: Lifetime, extent: Extent) -> Result<(), &str> {
+        {
+            let info = self.lock_info_mut(range);
+            let extents = info
+                .suspended
+                .get(&lifetime)
+                .ok_or("lifetime has no suspended lock over this range")?;
+            extents
+                .iter()
+                .position(|e| *e == extent)
+                .ok_or("extent is not outstanding for this lifetime")?;
+        }
+
+        // Scan every overlapping range, not just the one keyed identically to `range` (same
+        // predicate `acquire` uses): someone could have acquired a lock over a different but
+        // overlapping range while this one was suspended.
+        let blocked = self.locks.iter().any(|(r, info)| {
+            r.overlaps(range)
+                && match &info.active {
+                    Lock::NoLock => false,
+                    Lock::ReadLock(holders) => holders.iter().any(|&l| l != lifetime),
+                    Lock::WriteLock(owner) => *owner != lifetime,
+                }
+        });
+        if blocked {
+            return Err("range was locked by someone else while suspended");
+        }
+
+        let info = self.lock_info_mut(range);
+        let extents = info
+            .suspended
+            .get_mut(&lifetime)
+            .expect("checked above that this lifetime has a suspended entry over this range");
+        let pos = extents
+            .iter()
+            .position(|e| *e == extent)
+            .expect("checked above that this extent is outstanding for this lifetime");
+        extents.remove(pos);
+        if extents.is_empty() {
+            info.suspended.remove(&lifetime);
+            info.active = Lock::WriteLock(lifetime);
+        }
+        Ok(())
+    }
+
+    /// Whether `kind` access to `range` is permitted under the current borrow-lock state.
+    ///
+    /// A read is permitted unless an active `WriteLock` held by a different lifetime covers
+    /// the range. A write is permitted only under no lock, or a `WriteLock` already owned by
+    /// `lifetime`.
+    #[must_use]
+    pub fn access_permitted(&self, range: AddrRange, kind: LockKind, lifetime: Lifetime) -> bool {
+        self.locks
+            .iter()
+            .filter(|(r, _)| r.overlaps(range))
+            .all(|(_, info)| match (&info.active, kind) {
+                (Lock::NoLock, _) | (Lock::ReadLock(_), LockKind::Read) => true,
+                (Lock::ReadLock(_), LockKind::Write) => false,
+                (Lock::WriteLock(owner), _) => *owner == lifetime,
+            })
+    }
+
+    /// Find (or create) the `LockInfo` tracked for exactly `range`.
+    fn lock_info_mut(&mut self, range: AddrRange) -> &mut LockInfo {
+        if let Some(pos) = self.locks.iter().position(|(r, _)| *r == range) {
+            &mut self.locks[pos].1
+        } else {
+            self.locks.push((range, LockInfo::default()));
+            &mut self.locks.last_mut().unwrap().1
+        }
+    }
+
+    /// Resolve a write fault against a mapping, on behalf of `lifetime`.
+    ///
+    /// On the first write into a copy-on-write region this allocates a fresh private
+    /// `DataSource`, copies the touched page out of the parent source at the mapping's
+    /// `offset`, and splits the owning `MapEntry` so only the touched sub-range is replaced
+    /// by a new non-`cow` writable entry pointing at the private copy. Untouched parts of the
+    /// original mapping keep sharing the parent `DataSource`. Mappings that aren't `cow` just
+    /// hand back their existing source, same as `get_source_for_addr`.
+    ///
+    /// # Errors
+    /// If `addr` does not fall inside any mapping, if the mapping isn't writable, or if
+    /// `lifetime` is not allowed to write under the current borrow-lock state (see
+    /// `access_permitted`).
+    pub fn resolve_write(
+        &mut self,
+        addr: VirtualAddress,
+        lifetime: Lifetime,
+    ) -> Result<(Arc<dyn DataSource>, usize), &str> {
+        let (is_cow, source, offset) = {
+            let (start, end, entry) =
+                self.mappings.get_containing(addr).ok_or("that address isn't mapped")?;
+            if !entry.flags.write {
+                return Err("wrong permissions");
+            }
+            let range = AddrRange { start, len: end - start };
+            if !self.access_permitted(range, LockKind::Write, lifetime) {
+                return Err("that range is locked against this access");
+            }
+            (entry.flags.cow, entry.source.clone(), entry.offset)
+        };
+
+        if !is_cow {
+            return Ok((source, offset));
+        }
+
+        let (_, _, entry) = self
+            .mappings
+            .remove_containing(addr)
+            .ok_or("that address isn't mapped")?;
+
+        let page_start = addr - (addr % COW_PAGE_SIZE);
+        let touched_start = page_start.max(entry.addr);
+        let touched_end = (page_start + COW_PAGE_SIZE).min(entry.addr + entry.span);
+        let touched_len = touched_end - touched_start;
+        let copy_offset = entry.offset + (touched_start - entry.addr);
+
+        let private: Arc<dyn DataSource> = Arc::new(CowCopy {
+            bytes: entry.source.get_bytes(copy_offset, touched_len),
+        });
+        let private_flags = entry.flags.toggle_cow();
+
+        if touched_start > entry.addr {
+            self.mappings
+                .insert(
+                    entry.addr,
+                    touched_start - entry.addr,
+                    MapEntry {
+                        source: entry.source.clone(),
+                        offset: entry.offset,
+                        span: touched_start - entry.addr,
+                        addr: entry.addr,
+                        flags: entry.flags,
+                    },
+                )
+                .unwrap_or_else(|_| unreachable!("touched_start - entry.addr is strictly inside the vacated range"));
+        }
+        self.mappings
+            .insert(
+                touched_start,
+                touched_len,
+                MapEntry {
+                    source: private.clone(),
+                    offset: 0,
+                    span: touched_len,
+                    addr: touched_start,
+                    flags: private_flags,
+                },
+            )
+            .unwrap_or_else(|_| unreachable!("the touched sub-range is inside the vacated range"));
+        if touched_end < entry.addr + entry.span {
+            self.mappings
+                .insert(
+                    touched_end,
+                    entry.addr + entry.span - touched_end,
+                    MapEntry {
+                        source: entry.source.clone(),
+                        offset: entry.offset + (touched_end - entry.addr),
+                        span: entry.addr + entry.span - touched_end,
+                        addr: touched_end,
+                        flags: entry.flags,
+                    },
+                )
+                .unwrap_or_else(|_| unreachable!("the remaining high sub-range is inside the vacated range"));
+        }
+
+        Ok((private, 0))
     }
 }
 
@@ -145,8 +849,6 @@ impl AddressSpace {
                                          // here because these directly correspond to yes/no
                                          // hardware flags
 pub struct FlagBuilder {
-    // TODO: should there be some sanity checks that conflicting flags are never toggled? can we do
-    // this at compile-time? (the second question is maybe hard)
     read: bool,
     write: bool,
     execute: bool,
@@ -256,5 +958,242 @@ impl FlagBuilder {
             shared,
         }
     }
+
+    #[must_use]
+    /// Return `true` if no flags are toggled on.
+    pub const fn is_empty(self) -> bool {
+        !(self.read || self.write || self.execute || self.cow || self.private || self.shared)
+    }
+
+    /// Reject nonsensical combinations of flags.
+    ///
+    /// # Errors
+    /// Returns the specific pair of flags that conflict, so callers can report *which*
+    /// combination was rejected rather than a generic failure.
+    pub const fn validate(self) -> Result<(), FlagError> {
+        if self.shared && self.private {
+            return Err(FlagError::SharedAndPrivate);
+        }
+        if self.cow && !self.write {
+            return Err(FlagError::CowWithoutWrite);
+        }
+        if self.cow && self.shared {
+            return Err(FlagError::CowAndShared);
+        }
+        Ok(())
+    }
+}
+
+/// The specific pair of conflicting flags rejected by [`FlagBuilder::validate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagError {
+    /// `shared` and `private` were both toggled on.
+    SharedAndPrivate,
+    /// `cow` was toggled on without `write`; a copy-on-write mapping is only meaningful if
+    /// writes to it are what triggers the copy.
+    CowWithoutWrite,
+    /// `cow` and `shared` were both toggled on; a COW mapping is always a private view of the
+    /// parent source.
+    CowAndShared,
+}
+
+impl FlagError {
+    /// A short, human-readable description of the conflict.
+    #[must_use]
+    pub const fn message(self) -> &'static str {
+        match self {
+            Self::SharedAndPrivate => "flags cannot be both shared and private",
+            Self::CowWithoutWrite => "flags cannot be cow without write",
+            Self::CowAndShared => "flags cannot be both cow and shared",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestSource(Vec<u8>);
+
+    impl DataSource for TestSource {
+        fn get_bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+            self.0[offset..offset + len].to_vec()
+        }
+    }
+
+    #[test]
+    fn first_fit_finds_leading_and_internal_gaps() {
+        let mut space = AddressSpace::new("test");
+        let a = space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 4096])), 0, 16, 16, FlagBuilder::read())
+            .map(|()| 16);
+        assert!(a.is_ok());
+        // [0, 16) is free and big enough for an 8-byte mapping.
+        assert_eq!(space.mappings.first_fit(8), 0);
+
+        // Fill the leading gap, then punch a 32-byte internal gap and confirm it's found.
+        space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 4096])), 0, 16, 0, FlagBuilder::read())
+            .unwrap();
+        space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 4096])), 0, 16, 64, FlagBuilder::read())
+            .unwrap();
+        assert_eq!(space.mappings.first_fit(32), 32);
+        assert_eq!(space.mappings.first_fit(64), 80);
+    }
+
+    #[test]
+    fn adjacent_ranges_do_not_overlap() {
+        let mut space = AddressSpace::new("test");
+        space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 16])), 0, 16, 0, FlagBuilder::read())
+            .unwrap();
+        // Touching at the shared endpoint is fine under half-open semantics.
+        assert!(space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 16])), 0, 16, 16, FlagBuilder::read())
+            .is_ok());
+        // But actually overlapping is rejected.
+        assert!(space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 16])), 0, 16, 20, FlagBuilder::read())
+            .is_err());
+    }
+
+    #[test]
+    fn rejected_insert_does_not_destroy_the_tree() {
+        let mut space = AddressSpace::new("test");
+        // Enough mappings that a later overlap is detected several levels below the root,
+        // not just at the root itself.
+        for i in 0..10 {
+            space
+                .add_mapping_at(Arc::new(TestSource(vec![0; 16])), 0, 16, i * 16, FlagBuilder::read())
+                .unwrap();
+        }
+
+        // Overlaps the mapping at [64, 80) and must be rejected...
+        assert!(space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 16])), 0, 16, 68, FlagBuilder::read())
+            .is_err());
+
+        // ...without losing any of the mappings that were already there.
+        for i in 0..10 {
+            assert!(
+                space.mappings.get_containing(i * 16 + 1).is_some(),
+                "mapping at index {i} was lost after an unrelated rejected insert"
+            );
+        }
+    }
+
+    #[test]
+    fn many_inserts_and_removals_keep_lookups_correct() {
+        let mut space = AddressSpace::new("test");
+        for i in 0..50 {
+            space
+                .add_mapping_at(
+                    Arc::new(TestSource(vec![0; 16])),
+                    0,
+                    16,
+                    i * 16,
+                    FlagBuilder::read(),
+                )
+                .unwrap();
+        }
+        for i in (0..50).step_by(2) {
+            space
+                .mappings
+                .remove_start(i * 16)
+                .expect("every even mapping was inserted");
+        }
+        for i in 0..50 {
+            let found = space.mappings.get_containing(i * 16 + 1).is_some();
+            assert_eq!(found, i % 2 == 1, "mapping at index {i} in wrong state after removal");
+        }
+    }
+
+    #[test]
+    fn write_fault_splits_off_a_private_copy() {
+        let mut space = AddressSpace::new("test");
+        let parent = Arc::new(TestSource(vec![1, 2, 3, 4]));
+        let flags = FlagBuilder::read().toggle_write().toggle_cow();
+        space.add_mapping_at(parent.clone(), 0, 4, 0, flags).unwrap();
+
+        // A read still goes straight to the shared parent source.
+        let (read_source, read_offset) = space
+            .get_source_for_addr(0, FlagBuilder::read(), 1)
+            .expect("mapping exists and is readable");
+        assert_eq!(read_source.get_bytes(read_offset, 4), vec![1, 2, 3, 4]);
+
+        // A write faults in a private copy with the same bytes, but no longer shares storage
+        // with further writes against the parent.
+        let (write_source, write_offset) = space
+            .get_source_for_addr(0, FlagBuilder::write(), 1)
+            .expect("cow mapping is writable");
+        assert_eq!(write_source.get_bytes(write_offset, 4), vec![1, 2, 3, 4]);
+
+        // The split mapping is no longer `cow`, so a second write resolves without faulting
+        // again (and without error).
+        assert!(space.get_source_for_addr(0, FlagBuilder::write(), 1).is_ok());
+    }
+
+    #[test]
+    fn write_denied_without_write_flag() {
+        let mut space = AddressSpace::new("test");
+        space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 4])), 0, 4, 0, FlagBuilder::read())
+            .unwrap();
+        assert!(space.get_source_for_addr(0, FlagBuilder::write(), 1).is_err());
+    }
+
+    #[test]
+    fn acquire_rejects_overlapping_write_from_another_lifetime() {
+        let mut space = AddressSpace::new("test");
+        let a = AddrRange { start: 0, len: 16 };
+        let b = AddrRange { start: 8, len: 16 };
+
+        space.acquire(a, LockKind::Write, 1).unwrap();
+        assert!(space.acquire(b, LockKind::Write, 2).is_err());
+
+        // Releasing the original lock frees the overlapping range back up.
+        space.release(a, 1).unwrap();
+        assert!(space.acquire(b, LockKind::Write, 2).is_ok());
+    }
+
+    #[test]
+    fn acquire_allows_reentrant_write_from_the_same_lifetime() {
+        let mut space = AddressSpace::new("test");
+        let a = AddrRange { start: 0, len: 16 };
+        let b = AddrRange { start: 8, len: 16 };
+        space.acquire(a, LockKind::Write, 1).unwrap();
+        assert!(space.acquire(b, LockKind::Write, 1).is_ok());
+    }
+
+    #[test]
+    fn reactivate_is_blocked_by_an_overlapping_lock_from_another_lifetime() {
+        let mut space = AddressSpace::new("test");
+        let a = AddrRange { start: 0, len: 16 };
+        let b = AddrRange { start: 8, len: 24 };
+
+        space.acquire(a, LockKind::Write, 1).unwrap();
+        space.suspend(a, 1, 99).unwrap();
+
+        // While lifetime 1's lock is suspended, lifetime 2 grabs the overlapping-but-different
+        // range `b`.
+        space.acquire(b, LockKind::Write, 2).unwrap();
+
+        // Lifetime 1 must not be able to reactivate over `b`'s overlapping write lock.
+        assert!(space.reactivate(a, 1, 99).is_err());
+    }
+
+    #[test]
+    fn remove_mapping_range_splits_a_single_mapping_in_two() {
+        let mut space = AddressSpace::new("test");
+        space
+            .add_mapping_at(Arc::new(TestSource(vec![0; 32])), 0, 32, 0, FlagBuilder::read())
+            .unwrap();
+        let unmapped = space.remove_mapping_range(8, 8);
+        assert_eq!(unmapped, 8);
+        assert!(space.mappings.get_containing(4).is_some());
+        assert!(space.mappings.get_containing(10).is_none());
+        assert!(space.mappings.get_containing(20).is_some());
+    }
 }
 